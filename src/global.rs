@@ -0,0 +1,44 @@
+// Not wired up as `#[global_allocator]`: the demo in `main` exercises the
+// `Stack` API directly instead. Kept available for anyone embedding this crate.
+#![allow(dead_code)]
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::stack::{Stack, META_SIZE};
+
+/// Wraps a [`Stack`] behind a [`Mutex`] and implements [`GlobalAlloc`] over it.
+///
+/// [`GlobalStack::new`] is not a `const fn` (the `Stack` it builds keeps its
+/// buffer in a `Vec`), so a `GlobalStack` cannot initialise a
+/// `#[global_allocator]` static; its `GlobalAlloc` methods are called directly.
+pub(crate) struct GlobalStack(Mutex<Stack>);
+
+impl GlobalStack {
+    pub(crate) fn new(size: usize) -> Self {
+        GlobalStack(Mutex::new(Stack::new(size)))
+    }
+}
+
+unsafe impl GlobalAlloc for GlobalStack {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut stack = self.0.lock().unwrap();
+        let base = stack.data_ptr();
+
+        match stack.allocate_aligned(layout.size(), layout.align()) {
+            Ok(meta) => base.add(meta.addr + META_SIZE + meta.offset),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut stack = self.0.lock().unwrap();
+        let base = stack.data_ptr();
+        let payload_addr = ptr.offset_from(base) as usize;
+
+        if let Ok(meta) = stack.locate_aligned(payload_addr, layout.align()) {
+            let _ = stack.release(meta.addr);
+        }
+    }
+}
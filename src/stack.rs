@@ -0,0 +1,602 @@
+use anyhow::{anyhow, ensure, Result};
+
+/// 1 flags byte + a `u16` refcount + a `u32` size + a `u32` prev offset.
+pub(crate) const META_SIZE: usize = 11;
+
+#[derive(Debug)]
+pub(crate) struct Meta {
+    pub(crate) addr: usize,
+    pub(crate) free: bool,
+    pub(crate) size: usize,
+    pub(crate) prev: Option<usize>,
+    pub(crate) next: Option<usize>,
+    /// Bytes between `addr + META_SIZE` and the real (aligned) payload start.
+    /// Non-zero only right after `allocate_aligned` returns a `Meta`; it isn't
+    /// persisted, since `size` already accounts for it on disk.
+    pub(crate) offset: usize,
+    /// Number of live owners. `1` right after [`Stack::allocate`], `0` on a
+    /// block carved by [`Stack::reserve`] that hasn't been claimed yet, and
+    /// always `0` on a free block. [`Stack::release`] only hands the block
+    /// back to the free-list coalescing in [`Stack::free`] once this hits 0.
+    pub(crate) count: usize,
+}
+
+/// A safe, owning reference into a slice of the arena, returned by
+/// [`Stack::alloc_slice`]. Read/write through [`Stack::read`],
+/// [`Stack::write`] and [`Stack::as_mut_slice`]; hand it back with
+/// [`Stack::dealloc`], which consumes it so it can't be used afterwards.
+pub(crate) struct Handle {
+    addr: usize,
+    len: usize,
+}
+
+pub(crate) struct Stack {
+    stack: Vec<u8>,
+    chunk_size: usize,
+    /// Next-fit rover: where the next `allocate` scan starts, so allocations
+    /// don't keep re-scanning (and clustering in) the low end of the heap.
+    alloc_begin: usize,
+    /// Upper bound the backing buffer may grow to on ENOSPC. `None` means the
+    /// buffer is fixed-size (the only mode safe to back a `#[global_allocator]`
+    /// with, since growing a `Vec` can reallocate and invalidate pointers
+    /// already handed out to callers).
+    growth_limit: Option<usize>,
+}
+
+impl Stack {
+    pub(crate) fn new(size: usize) -> Stack {
+        let chunk_size = 16;
+        assert!(
+            chunk_size >= META_SIZE,
+            "chunk_size ({chunk_size}) must be at least META_SIZE ({META_SIZE})"
+        );
+
+        // The tail block's `limit` is always `self.stack.len()`, and the
+        // split/no-split decision in `carve` assumes a chunk-aligned `limit`
+        // (like every other address in the header chain). A non-aligned
+        // buffer end would let `read_meta` derive a `next` that points a few
+        // bytes past the real end of the backing `Vec`.
+        let size = Self::round_up_to_chunk(size, chunk_size);
+
+        let mut stack = Stack {
+            stack: vec![0; size],
+            chunk_size,
+            alloc_begin: 0,
+            growth_limit: None,
+        };
+
+        stack
+            .write_meta(&Meta {
+                addr: 0,
+                free: true,
+                prev: None,
+                next: None,
+                size: size - META_SIZE,
+                offset: 0,
+                count: 0,
+            })
+            .expect("Failed writing stack init");
+
+        stack
+    }
+
+    /// Like [`Stack::new`], but on ENOSPC the backing buffer is doubled (capped
+    /// at `limit`) and the allocation retried instead of failing outright.
+    ///
+    /// Not safe to use behind `#[global_allocator]`: growing the `Vec` may
+    /// reallocate, invalidating any pointer already returned to a caller.
+    pub(crate) fn with_limit(initial: usize, limit: usize) -> Stack {
+        let mut stack = Stack::new(initial);
+        stack.growth_limit = Some(Self::round_up_to_chunk(limit, stack.chunk_size));
+        stack
+    }
+
+    fn last_meta(&self) -> Result<Meta> {
+        let mut meta = self.read_meta(0)?;
+
+        while let Some(next) = meta.next {
+            meta = self.read_meta(next)?;
+        }
+
+        Ok(meta)
+    }
+
+    /// Doubles the backing buffer (capped at `growth_limit`) and extends the
+    /// tail block to cover the new space, appending a fresh free block if the
+    /// tail was allocated.
+    fn grow(&mut self) -> Result<()> {
+        let limit = self.growth_limit.expect("grow is only called when growth_limit is set");
+        let old_len = self.stack.len();
+        let new_len = (old_len * 2).min(limit);
+
+        // Found before resizing: `read_meta`'s `next` derivation trusts
+        // `self.stack.len()` as the end of valid memory, so running it after
+        // the resize would wander into the freshly zeroed (uninitialized) tail.
+        let mut tail = self.last_meta()?;
+
+        self.stack.resize(new_len, 0);
+
+        if tail.free {
+            tail.size += new_len - old_len;
+            self.write_meta(&tail)?;
+        } else {
+            self.write_meta(&Meta {
+                addr: old_len,
+                free: true,
+                size: new_len - old_len - META_SIZE,
+                prev: Some(tail.addr),
+                next: None,
+                offset: 0,
+                count: 0,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Pointer to the start of the backing buffer, used to translate a `Meta::addr`
+    /// into a real pointer (and back) for the `GlobalAlloc` impl.
+    pub(crate) fn data_ptr(&mut self) -> *mut u8 {
+        self.stack.as_mut_ptr()
+    }
+
+    fn ceil(&self, size: usize) -> usize {
+        Self::round_up_to_chunk(size, self.chunk_size)
+    }
+
+    fn round_up_to_chunk(size: usize, chunk_size: usize) -> usize {
+        (size as f64 / chunk_size as f64).ceil() as usize * chunk_size
+    }
+
+    pub(crate) fn read_meta(&self, addr: usize) -> Result<Meta> {
+        let flags = self.stack[addr];
+        let free = flags & 1 == 1;
+
+        let count = u16::from_le_bytes(self.stack[addr + 1..addr + 3].try_into().unwrap()) as usize;
+        let size = u32::from_le_bytes(self.stack[addr + 3..addr + 7].try_into().unwrap()) as usize;
+        let prev = u32::from_le_bytes(self.stack[addr + 7..addr + 11].try_into().unwrap()) as usize;
+        let prev = if prev < addr { Some(prev) } else { None };
+
+        let next = self.ceil(addr + META_SIZE + size);
+        let next = if addr < next && next < self.stack.len() {
+            Some(next)
+        } else {
+            None
+        };
+
+        Ok(Meta {
+            addr,
+            free,
+            size,
+            prev,
+            next,
+            offset: 0,
+            count,
+        })
+    }
+
+    fn write_meta(&mut self, meta: &Meta) -> Result<()> {
+        self.stack[meta.addr] = meta.free as u8;
+        self.stack[meta.addr + 1..meta.addr + 3].copy_from_slice(&(meta.count as u16).to_le_bytes());
+        self.stack[meta.addr + 3..meta.addr + 7].copy_from_slice(&(meta.size as u32).to_le_bytes());
+        self.stack[meta.addr + 7..meta.addr + 11]
+            .copy_from_slice(&(meta.prev.unwrap_or(0) as u32).to_le_bytes());
+
+        Ok(())
+    }
+
+    /// First address `>= addr + META_SIZE` that satisfies `align`.
+    fn aligned_start(addr: usize, align: usize) -> usize {
+        let base = addr + META_SIZE;
+        let rem = base % align;
+
+        if rem == 0 {
+            base
+        } else {
+            base + (align - rem)
+        }
+    }
+
+    /// Finds a free block able to host `size` bytes aligned to `align`, scanning
+    /// forward from `addr` and stopping once the chain reaches `end`. If
+    /// nothing fits before `end` and this is the first pass (`wrapped` is
+    /// false), wraps around to address 0 and scans up to the original `begin`
+    /// once before giving up, so a full logical sweep happens exactly once.
+    /// The returned `Meta::offset` is the padding between the block's natural
+    /// payload start and the aligned address; callers fold it into `size` so
+    /// the padding stays accounted for and coalescing keeps working off a
+    /// single contiguous span.
+    #[allow(clippy::too_many_arguments)]
+    fn find_free(
+        &self,
+        size: usize,
+        align: usize,
+        addr: usize,
+        begin: usize,
+        end: usize,
+        wrapped: bool,
+    ) -> Result<Meta> {
+        let mut meta = self.read_meta(addr)?;
+
+        if meta.free {
+            let offset = Self::aligned_start(meta.addr, align) - (meta.addr + META_SIZE);
+
+            if offset + size <= meta.size {
+                meta.offset = offset;
+                return Ok(meta);
+            }
+        }
+
+        if let Some(next) = meta.next {
+            if next < end {
+                return self.find_free(size, align, next, begin, end, wrapped);
+            }
+        }
+
+        if !wrapped && begin > 0 {
+            return self.find_free(size, align, 0, begin, begin, true);
+        }
+
+        Err(anyhow!("Not enough room in stack"))
+    }
+
+    pub(crate) fn allocate(&mut self, size: usize) -> Result<Meta> {
+        self.allocate_aligned(size, 1)
+    }
+
+    pub(crate) fn allocate_aligned(&mut self, size: usize, align: usize) -> Result<Meta> {
+        self.carve(size, align, 1)
+    }
+
+    /// Carves out a block that is committed (non-free) but not yet owned:
+    /// its reference count starts at 0 instead of the 1 [`Stack::allocate`]
+    /// uses. Call [`Stack::retain`] once an owner claims it, and
+    /// [`Stack::release`] to hand ownership back.
+    pub(crate) fn reserve(&mut self, size: usize) -> Result<Meta> {
+        self.carve(size, 1, 0)
+    }
+
+    /// Increments the reference count of the block at `addr`.
+    pub(crate) fn retain(&mut self, addr: usize) -> Result<()> {
+        let mut meta = self.read_meta(addr)?;
+        ensure!(!meta.free, "retain on a free block");
+
+        meta.count += 1;
+        self.write_meta(&meta)
+    }
+
+    /// Decrements the reference count of the block at `addr`, coalescing it
+    /// back into the free list via [`Stack::free`] once the count hits 0.
+    pub(crate) fn release(&mut self, addr: usize) -> Result<()> {
+        let mut meta = self.read_meta(addr)?;
+        ensure!(!meta.free, "release on a free block");
+
+        meta.count = meta.count.saturating_sub(1);
+
+        if meta.count == 0 {
+            self.free(meta)?;
+        } else {
+            self.write_meta(&meta)?;
+        }
+
+        Ok(())
+    }
+
+    /// Shared by [`Stack::allocate_aligned`] and [`Stack::reserve`]: finds
+    /// (growing the buffer if needed) and splits off a free block, handing
+    /// back a committed `Meta` with `count` set as requested.
+    fn carve(&mut self, size: usize, align: usize, count: usize) -> Result<Meta> {
+        assert!(align.is_power_of_two(), "align must be a power of two");
+
+        let mut meta = loop {
+            let begin = self.alloc_begin;
+            let end = self.stack.len();
+
+            match self.find_free(size, align, begin, begin, end, false) {
+                Ok(meta) => break meta,
+                Err(err) => match self.growth_limit {
+                    Some(limit) if self.stack.len() < limit => self.grow()?,
+                    _ => return Err(err),
+                },
+            }
+        };
+
+        meta.free = false;
+        meta.count = count;
+        meta.size = meta.offset + size;
+
+        let limit = meta.next.unwrap_or(self.stack.len());
+        let next_addr = self.ceil(meta.addr + META_SIZE + meta.size);
+
+        if limit - next_addr >= self.chunk_size {
+            self.write_meta(&Meta {
+                addr: next_addr,
+                free: true,
+                size: limit - next_addr - META_SIZE,
+                prev: Some(meta.addr),
+                next: meta.next.take(),
+                offset: 0,
+                count: 0,
+            })?;
+
+            meta.next = Some(next_addr);
+        }
+
+        self.write_meta(&meta)?;
+
+        self.alloc_begin = meta.next.unwrap_or(0);
+
+        Ok(meta)
+    }
+
+    /// Recovers the header of an aligned allocation from the pointer handed
+    /// out to the caller. Walks the real header chain (like [`Stack::collect`])
+    /// looking for the block whose alignment padding lines up with
+    /// `payload_addr`, rather than guessing a header address from `payload_addr`
+    /// directly: a guess can land inside a block's own (zeroed) payload, which
+    /// happens to decode as a plausible but bogus `Meta`.
+    pub(crate) fn locate_aligned(&self, payload_addr: usize, align: usize) -> Result<Meta> {
+        let mut meta = self.read_meta(0)?;
+
+        loop {
+            if !meta.free && Self::aligned_start(meta.addr, align) == payload_addr {
+                return Ok(meta);
+            }
+
+            match meta.next {
+                Some(next) => meta = self.read_meta(next)?,
+                None => break,
+            }
+        }
+
+        Err(anyhow!("No allocation found for pointer"))
+    }
+
+    pub(crate) fn free(&mut self, mut meta: Meta) -> Result<Meta> {
+        meta.free = true;
+        meta.count = 0;
+        // Must match the canonical `next = ceil(addr + META_SIZE + size)` used
+        // everywhere else, not just `ceil(size)`: those only coincide when
+        // META_SIZE is itself a multiple of chunk_size, which it no longer is.
+        meta.size = self.ceil(meta.addr + META_SIZE + meta.size) - meta.addr - META_SIZE;
+
+        if let Some(prev) = meta.prev {
+            let prev = self.read_meta(prev)?;
+
+            if prev.free {
+                // A free block always carries count 0 (set here, the only
+                // place a block becomes free), so merging never drops a live
+                // reference.
+                debug_assert_eq!(prev.count, 0, "coalesced a free block with a nonzero refcount");
+
+                meta.size += meta.addr - prev.addr;
+                meta.addr = prev.addr;
+                meta.prev = prev.prev;
+            }
+        }
+
+        if let Some(next) = meta.next {
+            let mut next = self.read_meta(next)?;
+
+            if next.free {
+                debug_assert_eq!(next.count, 0, "coalesced a free block with a nonzero refcount");
+
+                meta.size += META_SIZE + next.size;
+
+                if let Some(next) = next.next {
+                    let mut next = self.read_meta(next)?;
+
+                    next.prev = Some(meta.addr);
+
+                    self.write_meta(&next)?;
+                }
+            } else {
+                next.prev = Some(meta.addr);
+
+                self.write_meta(&next)?;
+            }
+        }
+
+        self.write_meta(&meta)?;
+
+        let region_end = meta.addr + META_SIZE + meta.size;
+
+        if self.alloc_begin >= meta.addr && self.alloc_begin < region_end {
+            self.alloc_begin = meta.addr;
+        }
+
+        Ok(meta)
+    }
+
+    pub(crate) fn collect(&self) -> Result<Vec<Meta>> {
+        let mut meta = self.read_meta(0)?;
+        let mut next = meta.next;
+        let mut res = vec![meta];
+
+        while let Some(addr) = next {
+            meta = self.read_meta(addr)?;
+            next = meta.next;
+            res.push(meta);
+        }
+
+        Ok(res)
+    }
+
+    pub(crate) fn print(&self, msg: &str) -> Result<()> {
+        let metas = self.collect()?;
+
+        for meta in metas {
+            println!("{}: {:?}", msg, meta);
+        }
+
+        Ok(())
+    }
+
+    /// Allocates `len` bytes and hands back a [`Handle`] owning them.
+    pub(crate) fn alloc_slice(&mut self, len: usize) -> Result<Handle> {
+        let meta = self.allocate(len)?;
+
+        Ok(Handle { addr: meta.addr, len })
+    }
+
+    /// Copies `src` into the slice owned by `handle`. Errors if `src` is
+    /// longer than the handle's recorded length rather than truncating or
+    /// spilling into the next block.
+    pub(crate) fn write(&mut self, handle: &Handle, src: &[u8]) -> Result<()> {
+        ensure!(
+            src.len() <= handle.len,
+            "write of {} bytes exceeds handle capacity of {}",
+            src.len(),
+            handle.len
+        );
+
+        let start = handle.addr + META_SIZE;
+        self.stack[start..start + src.len()].copy_from_slice(src);
+
+        Ok(())
+    }
+
+    /// Borrows the slice owned by `handle`.
+    pub(crate) fn read(&self, handle: &Handle) -> &[u8] {
+        let start = handle.addr + META_SIZE;
+        &self.stack[start..start + handle.len]
+    }
+
+    /// Mutably borrows the slice owned by `handle`.
+    pub(crate) fn as_mut_slice(&mut self, handle: &Handle) -> &mut [u8] {
+        let start = handle.addr + META_SIZE;
+        &mut self.stack[start..start + handle.len]
+    }
+
+    /// Releases the block owned by `handle`, consuming it so the
+    /// now-dangling slice can no longer be read or written through it. Goes
+    /// through [`Stack::release`] rather than [`Stack::free`] directly, so a
+    /// block still `retain`ed elsewhere survives this call.
+    pub(crate) fn dealloc(&mut self, handle: Handle) -> Result<()> {
+        self.release(handle.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_allocation_round_trips_through_locate_and_free() {
+        let mut stack = Stack::new(128);
+
+        let meta = stack.allocate_aligned(10, 32).unwrap();
+        let payload_addr = meta.addr + META_SIZE + meta.offset;
+        assert_eq!(payload_addr % 32, 0, "payload must satisfy the requested alignment");
+
+        let located = stack.locate_aligned(payload_addr, 32).unwrap();
+        assert_eq!(located.addr, meta.addr, "locate_aligned must recover the same header");
+
+        stack.free(located).unwrap();
+    }
+
+    #[test]
+    fn next_fit_cursor_survives_a_coalesce_that_swallows_it() {
+        let mut stack = Stack::new(64);
+
+        // Lay out three blocks by hand: an allocated block at 0, a free block
+        // at 16, and an allocated block at 32 running to the end. Park the
+        // rover on the free block in the middle, then free block 0 so it
+        // coalesces with it — the merged block's address is 0, not 16, so the
+        // rover must move or it'd point at a stale header inside the merged
+        // block's payload.
+        stack
+            .write_meta(&Meta {
+                addr: 0,
+                free: false,
+                size: 1,
+                prev: None,
+                next: Some(16),
+                offset: 0,
+                count: 1,
+            })
+            .unwrap();
+        stack
+            .write_meta(&Meta {
+                addr: 16,
+                free: true,
+                size: 5,
+                prev: Some(0),
+                next: Some(32),
+                offset: 0,
+                count: 0,
+            })
+            .unwrap();
+        stack
+            .write_meta(&Meta {
+                addr: 32,
+                free: false,
+                size: 21,
+                prev: Some(16),
+                next: None,
+                offset: 0,
+                count: 1,
+            })
+            .unwrap();
+        stack.alloc_begin = 16;
+
+        let block_a = stack.read_meta(0).unwrap();
+        stack.free(block_a).unwrap();
+
+        assert_eq!(stack.alloc_begin, 0, "rover must move off the address the coalesce absorbed");
+
+        // The rover must still be usable afterwards, not just numerically updated.
+        stack.allocate(4).unwrap();
+    }
+
+    #[test]
+    fn grow_extends_a_free_tail_in_place() {
+        let mut stack = Stack::with_limit(16, 64);
+
+        let before = stack.last_meta().unwrap();
+        assert!(before.free, "the only block in a fresh stack is free");
+
+        stack.grow().unwrap();
+
+        let after = stack.last_meta().unwrap();
+        assert_eq!(after.addr, before.addr, "a free tail grows in place, not as a new block");
+        assert_eq!(after.size, before.size + 16, "tail size must absorb exactly the new space");
+        assert!(after.next.is_none());
+    }
+
+    #[test]
+    fn grow_appends_a_new_block_after_an_allocated_tail() {
+        let mut stack = Stack::with_limit(16, 64);
+        let tail = stack.allocate(5).unwrap();
+        assert!(!tail.free, "fully carving the only block leaves no room to split off a free tail");
+        assert!(tail.next.is_none());
+
+        stack.grow().unwrap();
+
+        let grown = stack.read_meta(16).unwrap();
+        assert!(grown.free, "growth past an allocated tail must append a fresh free block");
+        assert_eq!(grown.prev, Some(tail.addr));
+        assert_eq!(grown.size, 32 - 16 - META_SIZE);
+    }
+
+    #[test]
+    fn reserve_retain_release_tracks_refcount() {
+        let mut stack = Stack::new(64);
+
+        let meta = stack.reserve(8).unwrap();
+        assert_eq!(meta.count, 0, "reserve hands back an unclaimed block");
+
+        stack.retain(meta.addr).unwrap();
+        stack.retain(meta.addr).unwrap();
+        assert_eq!(stack.read_meta(meta.addr).unwrap().count, 2);
+
+        stack.release(meta.addr).unwrap();
+        let still_held = stack.read_meta(meta.addr).unwrap();
+        assert!(!still_held.free, "block must survive a release while a reference remains");
+        assert_eq!(still_held.count, 1);
+
+        stack.release(meta.addr).unwrap();
+        let freed = stack.read_meta(meta.addr).unwrap();
+        assert!(freed.free, "the last release must hand the block back to the free list");
+    }
+}